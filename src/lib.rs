@@ -1,58 +1,423 @@
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
-use futures::{FutureExt as _, StreamExt};
+use async_trait::async_trait;
+use directories::ProjectDirs;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt as _, StreamExt};
 use libp2p::{
-    dcutr, identify, identity, noise, ping, relay,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, Swarm,
+    connection_limits::{self, ConnectionLimits},
+    core::{muxing::StreamMuxerBox, transport::Boxed, upgrade::Version, Transport as _},
+    autonat, dcutr, gossipsub, identify, identity,
+    multiaddr::Protocol,
+    noise, ping, relay, rendezvous, request_response,
+    swarm::{dial_opts::DialOpts, ConnectedPoint, NetworkBehaviour, NetworkInfo, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
 };
+use libp2p_pnet::{PnetConfig, PreSharedKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tracing::{info, warn};
 
+/// How often a node re-publishes its roster announcement.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a roster entry survives without a fresh announcement.
+const ROSTER_TTL: Duration = Duration::from_secs(120);
+/// Largest accepted application-level ping/pong message.
+const MAX_PING_MESSAGE_SIZE: usize = 1024;
+/// Registration lifetime requested from the rendezvous point.
+const RENDEZVOUS_TTL_SECS: u64 = 2 * 60 * 60;
+/// How often we refresh our rendezvous registration, well ahead of its TTL.
+const RENDEZVOUS_REREGISTER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often we ask the rendezvous point for other members of our namespace.
+const RENDEZVOUS_DISCOVER_INTERVAL: Duration = Duration::from_secs(60);
+/// At most two established connections per peer: the initial relayed connection plus the
+/// direct one DCUtR dials alongside it while the hole punch is in flight. A cap of 1 would
+/// have `connection_limits` deny every hole-punch attempt against an already-relayed peer.
+const MAX_CONNECTIONS_PER_PEER: u32 = 2;
+/// Ceiling on simultaneously established connections, inbound and outbound.
+const MAX_ESTABLISHED_INCOMING: u32 = 128;
+const MAX_ESTABLISHED_OUTGOING: u32 = 128;
+/// Once the mesh has more peers than this times our connection ceiling, we start shedding
+/// the lowest-value (relayed) connections in favor of direct, DCUtR-upgraded ones.
+const RELAYED_EXCESS_FACTOR: f64 = 1.5;
+/// Base and cap for the exponential backoff applied to hole-punch retries.
+const HOLE_PUNCH_RETRY_BASE: Duration = Duration::from_secs(10);
+const HOLE_PUNCH_RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+/// How long `ping` waits for DCUtR to upgrade the relayed connection to a direct one before
+/// sending the application-level ping over whatever connection is up.
+const PING_DIRECT_WAIT: Duration = Duration::from_secs(5);
+/// How often the swarm loop polls the peer manager for retries and excess connections.
+const PEER_MANAGER_POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether a connection to a peer goes through the relay or directly, as classified from the
+/// endpoint's address at `ConnectionEstablished` time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionKind {
+    Relayed,
+    Direct,
+}
+
+fn connection_kind(endpoint: &ConnectedPoint) -> ConnectionKind {
+    let address = match endpoint {
+        ConnectedPoint::Dialer { address, .. } => address,
+        ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+    };
+
+    if address.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+        ConnectionKind::Relayed
+    } else {
+        ConnectionKind::Direct
+    }
+}
+
+/// What the peer manager knows about a connected peer.
+#[derive(Debug, Clone)]
+struct PeerState {
+    kind: ConnectionKind,
+    last_seen: Instant,
+    failure_count: u32,
+    next_retry_at: Instant,
+}
+
+/// Tracks relayed-vs-direct connection state per peer, retrying hole punching for peers stuck
+/// behind the relay (with exponential backoff) and identifying excess relayed connections to
+/// shed once the mesh outgrows our connection ceiling.
+#[derive(Debug, Default)]
+struct PeerManager {
+    peers: HashMap<PeerId, PeerState>,
+    /// Peers we disconnected ourselves to force a hole-punch redial. Their `ConnectionClosed`
+    /// is expected and must not wipe the backoff state `on_hole_punch_failed` just computed.
+    pending_retry_disconnects: HashSet<PeerId>,
+}
+
+impl PeerManager {
+    fn on_connected(&mut self, peer_id: PeerId, kind: ConnectionKind) {
+        self.peers
+            .entry(peer_id)
+            .and_modify(|state| {
+                state.kind = kind;
+                state.last_seen = Instant::now();
+            })
+            .or_insert(PeerState {
+                kind,
+                last_seen: Instant::now(),
+                failure_count: 0,
+                next_retry_at: Instant::now(),
+            });
+    }
+
+    /// Marks `peer_id` as about to be disconnected by us (for a hole-punch retry redial) so
+    /// the resulting `on_disconnected` keeps its backoff state instead of wiping it.
+    fn mark_retry_disconnect(&mut self, peer_id: PeerId) {
+        self.pending_retry_disconnects.insert(peer_id);
+    }
+
+    fn on_disconnected(&mut self, peer_id: &PeerId) {
+        if self.pending_retry_disconnects.remove(peer_id) {
+            return;
+        }
+        self.peers.remove(peer_id);
+    }
+
+    fn on_hole_punch_succeeded(&mut self, peer_id: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer_id) {
+            state.kind = ConnectionKind::Direct;
+            state.failure_count = 0;
+        }
+    }
+
+    fn on_hole_punch_failed(&mut self, peer_id: PeerId) {
+        if let Some(state) = self.peers.get_mut(&peer_id) {
+            state.failure_count = state.failure_count.saturating_add(1);
+            let backoff = HOLE_PUNCH_RETRY_BASE
+                .saturating_mul(1 << state.failure_count.min(6))
+                .min(HOLE_PUNCH_RETRY_MAX);
+            state.next_retry_at = Instant::now() + backoff;
+        }
+    }
+
+    /// Peers still stuck on a relayed connection whose backoff has elapsed.
+    fn peers_due_for_retry(&self) -> Vec<PeerId> {
+        let now = Instant::now();
+        self.peers
+            .iter()
+            .filter(|(_, state)| state.kind == ConnectionKind::Relayed && state.next_retry_at <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    /// Relayed peers to disconnect once the mesh has grown well past our connection ceiling,
+    /// oldest-seen first, so we make room for direct connections instead.
+    fn excess_relayed_peers(&self, max_connections: u32) -> Vec<PeerId> {
+        let capacity = (max_connections as f64 * RELAYED_EXCESS_FACTOR) as usize;
+        if self.peers.len() <= capacity {
+            return Vec::new();
+        }
+
+        let mut relayed: Vec<(PeerId, Instant)> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.kind == ConnectionKind::Relayed)
+            .map(|(peer_id, state)| (*peer_id, state.last_seen))
+            .collect();
+        relayed.sort_by_key(|(_, last_seen)| *last_seen);
+        relayed
+            .into_iter()
+            .take(self.peers.len() - capacity)
+            .map(|(peer_id, _)| peer_id)
+            .collect()
+    }
+}
+
 #[derive(NetworkBehaviour)]
 struct PatchLanBehavior {
     relay_client: relay::client::Behaviour,
     ping: ping::Behaviour,
     identify: identify::Behaviour,
     dcutr: dcutr::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    app_ping: request_response::Behaviour<PingCodec>,
+    autonat: autonat::Behaviour,
+    rendezvous: rendezvous::client::Behaviour,
+    connection_limits: connection_limits::Behaviour,
+}
+
+/// An application-level ping/pong exchanged over the tunnel, carrying an echoed nonce and
+/// timestamp so the round-trip time can be measured end to end rather than hop by hop.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum PingMessage {
+    Ping { nonce: u64, sent_at_millis: u64 },
+    Pong { nonce: u64, sent_at_millis: u64 },
+}
+
+/// A tiny length-prefixed JSON codec for [`PingMessage`].
+#[derive(Debug, Clone, Default)]
+struct PingCodec;
+
+#[async_trait]
+impl request_response::Codec for PingCodec {
+    type Protocol = StreamProtocol;
+    type Request = PingMessage;
+    type Response = PingMessage;
+
+    async fn read_request<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_ping_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &Self::Protocol, io: &mut T) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_ping_message(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        request: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_ping_message(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &Self::Protocol,
+        io: &mut T,
+        response: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_ping_message(io, &response).await
+    }
+}
+
+async fn read_ping_message<T: AsyncRead + Unpin + Send>(io: &mut T) -> io::Result<PingMessage> {
+    let mut len_buf = [0u8; 4];
+    io.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_PING_MESSAGE_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "ping message too large"));
+    }
+
+    let mut buf = vec![0u8; len];
+    io.read_exact(&mut buf).await?;
+    serde_json::from_slice(&buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+async fn write_ping_message<T: AsyncWrite + Unpin + Send>(
+    io: &mut T,
+    message: &PingMessage,
+) -> io::Result<()> {
+    let buf =
+        serde_json::to_vec(message).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    io.write_all(&(buf.len() as u32).to_be_bytes()).await?;
+    io.write_all(&buf).await?;
+    io.flush().await
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock before UNIX epoch")
+        .as_millis() as u64
+}
+
+/// Sends the application-level `Ping` request used by `PatchLan::ping`.
+fn send_ping(swarm: &mut Swarm<PatchLanBehavior>, peer: PeerId, nonce: u64) {
+    swarm.behaviour_mut().app_ping.send_request(
+        &peer,
+        PingMessage::Ping {
+            nonce,
+            sent_at_millis: now_millis(),
+        },
+    );
+}
+
+/// What the roster knows about a peer learned via gossipsub announcements.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addresses: Vec<Multiaddr>,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Announcement {
+    addresses: Vec<Multiaddr>,
 }
 
 pub struct PatchLan {
     swarm: Swarm<PatchLanBehavior>,
     relay_address: Multiaddr,
+    relay_peer_id: PeerId,
+    topic: gossipsub::IdentTopic,
+    namespace: rendezvous::Namespace,
+    roster: HashMap<PeerId, PeerInfo>,
+    nat_status: autonat::NatStatus,
+    nat_confidence: usize,
+    peer_manager: PeerManager,
 }
 
 impl PatchLan {
-    pub async fn connect(relay_address: Multiaddr, seed: u8) -> Result<PatchLan> {
-        let keypair = generate_ed25519(seed);
+    pub async fn connect(
+        relay_address: Multiaddr,
+        key: Option<String>,
+        seed: Option<u8>,
+    ) -> Result<PatchLan> {
+        // `--seed` is a hidden dev/test override that derives a reproducible throwaway
+        // keypair instead of touching the persistent identity on disk.
+        let (keypair, key) = match seed {
+            Some(seed) => (generate_ed25519(seed), key),
+            None => {
+                let identity = load_identity().context(
+                    "no persistent identity found; run `patchlan init` first, or pass --seed",
+                )?;
+                (identity.keypair, key.or(Some(identity.network_key)))
+            }
+        };
+
+        if key.is_some() && uses_quic(&relay_address) {
+            return Err(anyhow!(
+                "private network mode (--key) requires a TCP relay address; \
+                 the pnet PSK handshake cannot be carried over QUIC"
+            ));
+        }
+
+        let relay_peer_id = relay_address
+            .iter()
+            .find_map(|p| match p {
+                Protocol::P2p(peer_id) => Some(peer_id),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("relay address must include a /p2p/<peer-id> component"))?;
+
+        let psk = key.as_deref().map(derive_psk);
+        let private_network = psk.is_some();
+        let topic = network_topic(key.as_deref());
+        let namespace = network_namespace(key.as_deref());
 
         // Setup "swarm"
-        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
-            .with_tokio()
-            .with_tcp(
-                tcp::Config::default().nodelay(true),
-                noise::Config::new,
-                yamux::Config::default,
-            )?
-            .with_quic()
-            .with_dns()?
-            .with_relay_client(noise::Config::new, yamux::Config::default)?
-            .with_behaviour(|keypair, relay_behaviour| PatchLanBehavior {
-                relay_client: relay_behaviour,
-                ping: ping::Behaviour::new(ping::Config::new()),
-                identify: identify::Behaviour::new(identify::Config::new(
-                    "/patchlan/0.0.1".to_string(),
-                    keypair.public(),
-                )),
-                dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
-            })?
-            .build();
-
-        swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())?;
+        let mut swarm = if let Some(psk) = psk {
+            libp2p::SwarmBuilder::with_existing_identity(keypair)
+                .with_tokio()
+                .with_other_transport(|keypair| pnet_tcp_transport(keypair, psk))?
+                .with_dns()?
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(|keypair, relay_behaviour| {
+                    Ok(PatchLanBehavior {
+                        relay_client: relay_behaviour,
+                        ping: ping::Behaviour::new(ping::Config::new()),
+                        identify: identify::Behaviour::new(identify::Config::new(
+                            "/patchlan/0.0.1".to_string(),
+                            keypair.public(),
+                        )),
+                        dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
+                        gossipsub: new_gossipsub(keypair)?,
+                        app_ping: new_app_ping(),
+                        autonat: autonat::Behaviour::new(
+                            keypair.public().to_peer_id(),
+                            autonat::Config::default(),
+                        ),
+                        rendezvous: rendezvous::client::Behaviour::new(keypair.clone()),
+                        connection_limits: connection_limits::Behaviour::new(connection_limits()),
+                    })
+                })?
+                .build()
+        } else {
+            libp2p::SwarmBuilder::with_existing_identity(keypair)
+                .with_tokio()
+                .with_tcp(
+                    tcp::Config::default().nodelay(true),
+                    noise::Config::new,
+                    yamux::Config::default,
+                )?
+                .with_quic()
+                .with_dns()?
+                .with_relay_client(noise::Config::new, yamux::Config::default)?
+                .with_behaviour(|keypair, relay_behaviour| {
+                    Ok(PatchLanBehavior {
+                        relay_client: relay_behaviour,
+                        ping: ping::Behaviour::new(ping::Config::new()),
+                        identify: identify::Behaviour::new(identify::Config::new(
+                            "/patchlan/0.0.1".to_string(),
+                            keypair.public(),
+                        )),
+                        dcutr: dcutr::Behaviour::new(keypair.public().to_peer_id()),
+                        gossipsub: new_gossipsub(keypair)?,
+                        app_ping: new_app_ping(),
+                        autonat: autonat::Behaviour::new(
+                            keypair.public().to_peer_id(),
+                            autonat::Config::default(),
+                        ),
+                        rendezvous: rendezvous::client::Behaviour::new(keypair.clone()),
+                        connection_limits: connection_limits::Behaviour::new(connection_limits()),
+                    })
+                })?
+                .build()
+        };
+
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
         swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse().unwrap())?;
+        let mut listen_events_remaining = 1;
+        if !private_network {
+            swarm.listen_on("/ip4/0.0.0.0/udp/0/quic-v1".parse().unwrap())?;
+            listen_events_remaining = 2;
+        }
 
         let mut delay = futures_timer::Delay::new(Duration::from_secs(1)).fuse();
-        let mut listen_events_remaining = 2;
 
         // Wait for "new listen address" events for each interface
         loop {
@@ -100,6 +465,10 @@ impl PatchLan {
                     info!(address = %observed_addr, "got observed address from relay");
                     learned_observed_addr = true
                 }
+                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Gossipsub(_)) => {}
+                SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(_)) => {}
+                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Autonat(_)) => {}
+                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Rendezvous(_)) => {}
                 SwarmEvent::ConnectionEstablished { .. } => {}
                 SwarmEvent::NewListenAddr { .. } => {}
                 SwarmEvent::Dialing { .. } => {}
@@ -110,60 +479,356 @@ impl PatchLan {
                 break;
             }
         }
+
+        // Give AutoNAT a chance to classify our reachability before deciding whether
+        // we'll need a relay circuit reservation.
+        let mut nat_status = autonat::NatStatus::Unknown;
+        let mut nat_probe_timeout = futures_timer::Delay::new(Duration::from_secs(5)).fuse();
+        loop {
+            futures::select! {
+                event = swarm.next() => {
+                    match event.ok_or(anyhow!("EOF"))? {
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Autonat(
+                            autonat::Event::StatusChanged { new, .. },
+                        )) => {
+                            info!(?new, "AutoNAT classified reachability");
+                            nat_status = new;
+                            break;
+                        }
+                        _ => {}
+                    }
+                },
+                _ = nat_probe_timeout => {
+                    warn!("AutoNAT probe timed out; assuming private reachability");
+                    break;
+                }
+            }
+        }
+
+        // AutoNAT's confidence only grows with probe responses from distinct peers; with just
+        // the relay connected so far, it's usually still 0 here, so `nat_status` above should
+        // be read as a first guess rather than a settled answer until more peers join.
+        let nat_confidence = swarm.behaviour().autonat.confidence();
+
+        // Register under our network's namespace so other nodes can discover us through the
+        // relay instead of needing our peer ID passed on the command line.
+        swarm
+            .behaviour_mut()
+            .rendezvous
+            .register(namespace.clone(), relay_peer_id, Some(RENDEZVOUS_TTL_SECS))
+            .map_err(|err| anyhow!("failed to register with rendezvous point: {err}"))?;
+
         Ok(PatchLan {
             swarm,
             relay_address,
+            relay_peer_id,
+            topic,
+            namespace,
+            roster: HashMap::new(),
+            nat_status,
+            nat_confidence,
+            peer_manager: PeerManager::default(),
         })
     }
 
-    pub async fn listen(&mut self) -> Result<()> {
-        let swarm = &mut self.swarm;
-
-        // Listen on relay interface
-        swarm.listen_on(
-            self.relay_address
-                .clone()
-                .with(libp2p::multiaddr::Protocol::P2pCircuit),
-        )?;
-
-        while let Some(event) = swarm.next().await {
-            match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    info!("Listening on: {address}");
-
-                    // break;
-                    dbg!(swarm.network_info());
-                    dbg!(swarm.listeners().collect::<Vec<_>>());
-                    dbg!(swarm.local_peer_id());
-                    dbg!(swarm.external_addresses().collect::<Vec<_>>());
-                    dbg!(swarm.connected_peers().collect::<Vec<_>>());
+    /// The current set of peers learned through gossipsub announcements.
+    pub fn roster(&self) -> &HashMap<PeerId, PeerInfo> {
+        &self.roster
+    }
+
+    /// Our AutoNAT-determined reachability, as of the last status change.
+    pub fn nat_status(&self) -> &autonat::NatStatus {
+        &self.nat_status
+    }
+
+    /// How many distinct peers' AutoNAT probes back up `nat_status()`. Reads as low (often 0)
+    /// until the mesh has grown past the relay alone, so treat a fresh `Public`/`Private`
+    /// verdict with a low confidence as provisional.
+    pub fn nat_confidence(&self) -> usize {
+        self.nat_confidence
+    }
+
+    /// The relay server this node was configured to use.
+    pub fn relay_address(&self) -> &Multiaddr {
+        &self.relay_address
+    }
+
+    /// This node's persistent (or `--seed`-derived) PeerId.
+    pub fn local_peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Addresses we're currently listening on, including the relay circuit reservation.
+    pub fn listeners(&self) -> Vec<Multiaddr> {
+        self.swarm.listeners().cloned().collect()
+    }
+
+    /// Addresses other peers have told us they can reach us at.
+    pub fn external_addresses(&self) -> Vec<Multiaddr> {
+        self.swarm.external_addresses().cloned().collect()
+    }
+
+    /// Aggregate connection/listener counters, for `patchlan status`.
+    pub fn network_info(&self) -> NetworkInfo {
+        self.swarm.network_info()
+    }
+
+    /// Peers we currently hold an open connection to.
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        self.swarm.connected_peers().copied().collect()
+    }
+
+    /// Publishes this node's external addresses to the mesh topic and prunes stale entries.
+    fn announce_and_prune(&mut self) {
+        let announcement = Announcement {
+            addresses: self.swarm.external_addresses().cloned().collect(),
+        };
+
+        match serde_json::to_vec(&announcement) {
+            Ok(data) => {
+                if let Err(err) = self
+                    .swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(self.topic.clone(), data)
+                {
+                    warn!("failed to publish roster announcement: {err}");
                 }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(
-                    relay::client::Event::ReservationReqAccepted { .. },
-                )) => {
-                    info!("Relay accepted our request");
+            }
+            Err(err) => warn!("failed to encode roster announcement: {err}"),
+        }
+
+        self.roster
+            .retain(|_, info| info.last_seen.elapsed() < ROSTER_TTL);
+    }
+
+    /// Records an announcement from `peer_id` and, if newly discovered, dials it through the
+    /// relay circuit to trigger DCUtR hole punching.
+    fn handle_announcement(&mut self, peer_id: PeerId, announcement: Announcement) {
+        let already_known = self.roster.contains_key(&peer_id);
+
+        self.roster.insert(
+            peer_id,
+            PeerInfo {
+                addresses: announcement.addresses.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+
+        if !already_known {
+            self.dial_peer(peer_id, &announcement.addresses);
+        }
+    }
+
+    /// Dials `peer_id`, trying its advertised direct addresses before falling back to our
+    /// relay's circuit address (which also triggers DCUtR hole punching). A peer that's
+    /// publicly reachable and skipped the relay reservation has no circuit to dial, so
+    /// direct addresses must be tried too or such peers could never be reached this way.
+    /// No-op if we're already connected or `peer_id` is us.
+    fn dial_peer(&mut self, peer_id: PeerId, direct_addresses: &[Multiaddr]) {
+        if peer_id == *self.swarm.local_peer_id() || self.swarm.is_connected(&peer_id) {
+            return;
+        }
+
+        let addresses: Vec<Multiaddr> = direct_addresses
+            .iter()
+            .cloned()
+            .chain(std::iter::once(self.circuit_address(peer_id)))
+            .collect();
+
+        let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+        if let Err(err) = self.swarm.dial(opts) {
+            warn!(%peer_id, "failed to dial discovered peer: {err}");
+        }
+    }
+
+    /// Drops and re-dials a peer's relayed connection to retrigger a DCUtR hole-punch attempt,
+    /// used for peers stuck relayed whose retry backoff has elapsed.
+    fn retry_hole_punch(&mut self, peer_id: PeerId) {
+        self.peer_manager.mark_retry_disconnect(peer_id);
+        let _ = self.swarm.disconnect_peer_id(peer_id);
+
+        if let Err(err) = self.swarm.dial(self.circuit_address(peer_id)) {
+            warn!(%peer_id, "failed to redial for hole-punch retry: {err}");
+        }
+    }
+
+    fn circuit_address(&self, peer_id: PeerId) -> Multiaddr {
+        self.relay_address
+            .clone()
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(peer_id))
+    }
+
+    pub async fn listen(&mut self) -> Result<()> {
+        match self.nat_status.clone() {
+            autonat::NatStatus::Public(address) => {
+                info!(%address, "publicly reachable; skipping relay circuit reservation");
+                self.swarm.add_external_address(address);
+            }
+            autonat::NatStatus::Private | autonat::NatStatus::Unknown => {
+                // Reserve a slot on the relay so peers can reach us via `/p2p-circuit`.
+                self.swarm.listen_on(
+                    self.relay_address
+                        .clone()
+                        .with(Protocol::P2pCircuit),
+                )?;
+            }
+        }
+
+        let mut announce_interval = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut rendezvous_reregister_interval =
+            tokio::time::interval(RENDEZVOUS_REREGISTER_INTERVAL);
+        let mut rendezvous_discover_interval = tokio::time::interval(RENDEZVOUS_DISCOVER_INTERVAL);
+        let mut peer_manager_interval = tokio::time::interval(PEER_MANAGER_POLL_INTERVAL);
+
+        loop {
+            futures::select! {
+                _ = announce_interval.tick().fuse() => {
+                    self.announce_and_prune();
                 }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(event)) => {
-                    info!(?event);
+                _ = rendezvous_reregister_interval.tick().fuse() => {
+                    let (namespace, relay_peer_id) = (self.namespace.clone(), self.relay_peer_id);
+                    if let Err(err) = self.swarm.behaviour_mut().rendezvous.register(
+                        namespace,
+                        relay_peer_id,
+                        Some(RENDEZVOUS_TTL_SECS),
+                    ) {
+                        warn!("failed to refresh rendezvous registration: {err}");
+                    }
                 }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(event)) => {
-                    info!(?event);
+                _ = rendezvous_discover_interval.tick().fuse() => {
+                    let namespace = self.namespace.clone();
+                    self.swarm.behaviour_mut().rendezvous.discover(
+                        Some(namespace),
+                        None,
+                        None,
+                        self.relay_peer_id,
+                    );
                 }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Ping(_)) => {}
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint, ..
-                } => {
-                    info!(?peer_id, ?endpoint, "Connection established");
-                    dbg!(swarm.network_info());
-                    dbg!(swarm.listeners().collect::<Vec<_>>());
-                    dbg!(swarm.local_peer_id());
-                    dbg!(swarm.external_addresses().collect::<Vec<_>>());
-                    dbg!(swarm.connected_peers().collect::<Vec<_>>());
+                event = self.swarm.next() => {
+                    let Some(event) = event else { break };
+                    let swarm = &mut self.swarm;
+
+                    match event {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("Listening on: {address}");
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(
+                            relay::client::Event::ReservationReqAccepted { .. },
+                        )) => {
+                            info!("Relay accepted our request");
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(event)) => {
+                            info!(?event);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(dcutr::Event {
+                            remote_peer_id,
+                            result: Ok(_),
+                        })) => {
+                            info!(%remote_peer_id, "direct connection established via DCUtR");
+                            self.peer_manager.on_hole_punch_succeeded(remote_peer_id);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(dcutr::Event {
+                            remote_peer_id,
+                            result: Err(error),
+                        })) => {
+                            warn!(%remote_peer_id, "hole punch failed: {error}");
+                            self.peer_manager.on_hole_punch_failed(remote_peer_id);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Gossipsub(
+                            gossipsub::Event::Message { message, .. },
+                        )) => {
+                            if let (Some(peer_id), Ok(announcement)) = (
+                                message.source,
+                                serde_json::from_slice::<Announcement>(&message.data),
+                            ) {
+                                self.handle_announcement(peer_id, announcement);
+                            }
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Gossipsub(_)) => {}
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Rendezvous(
+                            rendezvous::client::Event::Discovered { registrations, .. },
+                        )) => {
+                            let discovered: Vec<(PeerId, Vec<Multiaddr>)> = registrations
+                                .iter()
+                                .map(|registration| {
+                                    (
+                                        registration.record.peer_id(),
+                                        registration.record.addresses().to_vec(),
+                                    )
+                                })
+                                .collect();
+
+                            for (peer_id, addresses) in discovered {
+                                self.dial_peer(peer_id, &addresses);
+                            }
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Rendezvous(
+                            rendezvous::client::Event::Registered { namespace, ttl, .. },
+                        )) => {
+                            info!(%namespace, ttl, "registered with rendezvous point");
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Rendezvous(event)) => {
+                            info!(?event);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(
+                            request_response::Event::Message {
+                                message:
+                                    request_response::Message::Request {
+                                        request, channel, ..
+                                    },
+                                ..
+                            },
+                        )) => {
+                            if let PingMessage::Ping { nonce, sent_at_millis } = request {
+                                let pong = PingMessage::Pong {
+                                    nonce,
+                                    sent_at_millis,
+                                };
+                                let _ = swarm.behaviour_mut().app_ping.send_response(channel, pong);
+                            }
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(_)) => {}
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Autonat(
+                            autonat::Event::StatusChanged { old, new },
+                        )) => {
+                            info!(?old, ?new, "AutoNAT reachability changed");
+                            self.nat_status = new;
+                            self.nat_confidence = swarm.behaviour().autonat.confidence();
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Autonat(_)) => {}
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Ping(_)) => {}
+                        SwarmEvent::ConnectionEstablished {
+                            peer_id, endpoint, ..
+                        } => {
+                            info!(?peer_id, ?endpoint, "Connection established");
+                            self.peer_manager.on_connected(peer_id, connection_kind(&endpoint));
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, .. } => {
+                            self.peer_manager.on_disconnected(&peer_id);
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                            info!(?peer_id, "Connection Failed: {error}");
+                        }
+                        _ => {}
+                    }
                 }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                    info!(?peer_id, "Connection Failed: {error}");
+                _ = peer_manager_interval.tick().fuse() => {
+                    for peer_id in self.peer_manager.peers_due_for_retry() {
+                        info!(%peer_id, "retrying hole punch for relayed peer");
+                        self.retry_hole_punch(peer_id);
+                    }
+
+                    for peer_id in self
+                        .peer_manager
+                        .excess_relayed_peers(MAX_ESTABLISHED_OUTGOING)
+                    {
+                        info!(%peer_id, "shedding excess relayed connection in favor of direct peers");
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        self.peer_manager.on_disconnected(&peer_id);
+                    }
                 }
-                _ => {}
             }
         }
 
@@ -171,67 +836,417 @@ impl PatchLan {
     }
 
     pub async fn ping(&mut self, peer: PeerId) -> Result<()> {
-        let swarm = &mut self.swarm;
-
-        swarm
+        self.swarm
             .dial(
                 self.relay_address
                     .clone()
-                    .with(libp2p::multiaddr::Protocol::P2pCircuit)
-                    .with(libp2p::multiaddr::Protocol::P2p(peer)),
+                    .with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(peer)),
             )
             .context("dial relay")?;
 
-        while let Some(event) = swarm.next().await {
-            match event {
-                SwarmEvent::NewListenAddr { address, .. } => {
-                    info!("Listening on: {address}");
-
-                    // break;
-                    dbg!(swarm.network_info());
-                    dbg!(swarm.listeners().collect::<Vec<_>>());
-                    dbg!(swarm.local_peer_id());
-                    dbg!(swarm.external_addresses().collect::<Vec<_>>());
-                    dbg!(swarm.connected_peers().collect::<Vec<_>>());
-                }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(
-                    relay::client::Event::ReservationReqAccepted { .. },
-                )) => {
-                    info!("Relay accepted our request");
-                }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(event)) => {
-                    info!(?event);
-                }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(event)) => {
-                    info!(?event);
-                }
-                SwarmEvent::Behaviour(PatchLanBehaviorEvent::Ping(_)) => {}
-                SwarmEvent::ConnectionEstablished {
-                    peer_id, endpoint, ..
-                } => {
-                    info!(?peer_id, ?endpoint, "Connection established");
-                    dbg!(swarm.network_info());
-                    dbg!(swarm.listeners().collect::<Vec<_>>());
-                    dbg!(swarm.local_peer_id());
-                    dbg!(swarm.external_addresses().collect::<Vec<_>>());
-                    dbg!(swarm.connected_peers().collect::<Vec<_>>());
-                }
-                SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
-                    info!(?peer_id, "Connection Failed: {error}");
+        let mut request_sent = false;
+        let mut direct = false;
+        let mut connected = false;
+        let mut direct_wait_elapsed = false;
+        let nonce = now_millis();
+        // Give DCUtR a chance to upgrade the initial relayed connection to a direct one before
+        // sending the application-level ping, so `direct` reflects the connection that actually
+        // carries it instead of always reading false.
+        let mut direct_wait = futures_timer::Delay::new(PING_DIRECT_WAIT).fuse();
+
+        loop {
+            futures::select! {
+                event = self.swarm.next() => {
+                    let event = event.ok_or(anyhow!("EOF"))?;
+                    let swarm = &mut self.swarm;
+                    match event {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            info!("Listening on: {address}");
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(
+                            relay::client::Event::ReservationReqAccepted { .. },
+                        )) => {
+                            info!("Relay accepted our request");
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::RelayClient(event)) => {
+                            info!(?event);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(dcutr::Event {
+                            remote_peer_id,
+                            result: Ok(_),
+                        })) if remote_peer_id == peer => {
+                            info!(%peer, "direct connection established via DCUtR");
+                            direct = true;
+                            if connected && !request_sent {
+                                request_sent = true;
+                                send_ping(swarm, peer, nonce);
+                            }
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Dcutr(event)) => {
+                            info!(?event);
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Gossipsub(_)) => {}
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(
+                            request_response::Event::Message {
+                                message:
+                                    request_response::Message::Response {
+                                        response: PingMessage::Pong { nonce: echoed, sent_at_millis },
+                                        ..
+                                    },
+                                ..
+                            },
+                        )) if echoed == nonce => {
+                            let rtt = Duration::from_millis(now_millis().saturating_sub(sent_at_millis));
+                            info!(%peer, direct, rtt_ms = rtt.as_millis(), "application-level pong");
+                            return Ok(());
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(
+                            request_response::Event::OutboundFailure { peer: failed_peer, error, .. },
+                        )) if failed_peer == peer => {
+                            return Err(anyhow!("application-level ping failed: {error}"));
+                        }
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::AppPing(_)) => {}
+                        SwarmEvent::Behaviour(PatchLanBehaviorEvent::Ping(_)) => {}
+                        SwarmEvent::ConnectionEstablished {
+                            peer_id, endpoint, ..
+                        } => {
+                            info!(?peer_id, ?endpoint, "Connection established");
+
+                            if peer_id == peer {
+                                connected = true;
+                                if direct_wait_elapsed && !request_sent {
+                                    request_sent = true;
+                                    send_ping(swarm, peer, nonce);
+                                }
+                            }
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                            info!(?peer_id, "Connection Failed: {error}");
+                        }
+                        _ => {}
+                    }
+                },
+                _ = direct_wait => {
+                    direct_wait_elapsed = true;
+                    if connected && !request_sent {
+                        info!(%peer, direct, "done waiting for a direct connection; pinging over what we have");
+                        request_sent = true;
+                        send_ping(&mut self.swarm, peer, nonce);
+                    }
                 }
-                _ => {}
             }
         }
+    }
+}
 
-        // TODO: do some sort of application-level ping
+/// A node's persistent libp2p keypair and network key, as written by `patchlan init`.
+pub struct Identity {
+    pub keypair: identity::Keypair,
+    pub network_key: String,
+}
 
-        Ok(())
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    keypair: Vec<u8>,
+    network_key: String,
+}
+
+/// Locates the identity file in the platform's standard config directory, e.g.
+/// `~/.config/patchlan/identity.json` on Linux.
+fn identity_path() -> Result<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "patchlan")
+        .ok_or_else(|| anyhow!("could not determine a config directory for this platform"))?;
+    Ok(dirs.config_dir().join("identity.json"))
+}
+
+/// Generates a fresh keypair and network key and writes them to the platform config
+/// directory. Refuses to clobber an existing identity.
+pub fn init_identity() -> Result<Identity> {
+    let path = identity_path()?;
+    if path.is_file() {
+        return Err(anyhow!(
+            "identity already exists at {}; remove it first if you really want a new one",
+            path.display()
+        ));
+    }
+
+    let mut network_key_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut network_key_bytes);
+
+    let identity = Identity {
+        keypair: identity::Keypair::generate_ed25519(),
+        network_key: to_hex(&network_key_bytes),
+    };
+    save_identity(&path, &identity)?;
+    Ok(identity)
+}
+
+/// Loads the identity previously written by `patchlan init`.
+pub fn load_identity() -> Result<Identity> {
+    let path = identity_path()?;
+    let bytes = fs::read(&path).with_context(|| {
+        format!(
+            "no identity at {}; run `patchlan init` first",
+            path.display()
+        )
+    })?;
+    let stored: StoredIdentity = serde_json::from_slice(&bytes)
+        .with_context(|| format!("parsing identity at {}", path.display()))?;
+    let keypair = identity::Keypair::from_protobuf_encoding(&stored.keypair)
+        .context("decoding stored keypair")?;
+
+    Ok(Identity {
+        keypair,
+        network_key: stored.network_key,
+    })
+}
+
+fn save_identity(path: &Path, identity: &Identity) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("creating config directory {}", parent.display()))?;
     }
+
+    let stored = StoredIdentity {
+        keypair: identity
+            .keypair
+            .to_protobuf_encoding()
+            .context("encoding keypair")?,
+        network_key: identity.network_key.clone(),
+    };
+    write_identity_file(path, &serde_json::to_vec_pretty(&stored)?)
 }
 
+/// Writes the identity file with owner-only permissions set from creation, so the private
+/// key is never briefly world/group-readable between the write and a follow-up chmod.
+#[cfg(unix)]
+fn write_identity_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("creating identity file {}", path.display()))?;
+    file.write_all(bytes)
+        .with_context(|| format!("writing identity to {}", path.display()))
+}
+
+#[cfg(not(unix))]
+fn write_identity_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes).with_context(|| format!("writing identity to {}", path.display()))
+}
+
+/// Derives a reproducible throwaway keypair for `--seed`, the hidden dev/test override
+/// that bypasses the persistent identity on disk.
 fn generate_ed25519(secret_key_seed: u8) -> identity::Keypair {
     let mut bytes = [0u8; 32];
     bytes[0] = secret_key_seed;
 
     identity::Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
 }
+
+/// Derives a 32-byte pnet pre-shared key from the `--key` network key text.
+fn derive_psk(key: &str) -> PreSharedKey {
+    let digest: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+    PreSharedKey::new(digest)
+}
+
+/// Whether `addr` would dial out over QUIC, which cannot carry the pnet PSK handshake.
+fn uses_quic(addr: &Multiaddr) -> bool {
+    use libp2p::multiaddr::Protocol;
+
+    addr.iter()
+        .any(|p| matches!(p, Protocol::QuicV1 | Protocol::Quic))
+}
+
+/// Derives the gossipsub topic nodes use to find each other: `patchlan/<hash-of-key>`.
+/// Networks with no `--key` share a single well-known public topic.
+fn network_topic(key: Option<&str>) -> gossipsub::IdentTopic {
+    let digest = Sha256::digest(key.unwrap_or("patchlan-public").as_bytes());
+    gossipsub::IdentTopic::new(format!("patchlan/{}", to_hex(&digest)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Derives the rendezvous namespace for this network key, so one relay can host many
+/// disjoint PatchLAN meshes without their memberships crossing over.
+fn network_namespace(key: Option<&str>) -> rendezvous::Namespace {
+    let digest = Sha256::digest(key.unwrap_or("patchlan-public").as_bytes());
+    rendezvous::Namespace::new(format!("patchlan/{}", to_hex(&digest)))
+        .expect("hex-encoded digest is a valid rendezvous namespace")
+}
+
+/// Message IDs keyed on publisher identity and sequence number (not just content), so that
+/// two peers publishing identical content (e.g. both with empty `external_addresses()` at
+/// startup) don't collide on the same id, and so a peer's periodic re-announcement of
+/// unchanged content isn't mistaken for a duplicate of its own earlier message.
+fn message_id_fn(message: &gossipsub::Message) -> gossipsub::MessageId {
+    let mut hasher = Sha256::new();
+    if let Some(source) = &message.source {
+        hasher.update(source.to_bytes());
+    }
+    if let Some(sequence_number) = message.sequence_number {
+        hasher.update(sequence_number.to_be_bytes());
+    }
+    hasher.update(&message.data);
+    gossipsub::MessageId::from(to_hex(&hasher.finalize()))
+}
+
+fn connection_limits() -> ConnectionLimits {
+    ConnectionLimits::default()
+        .with_max_established_per_peer(Some(MAX_CONNECTIONS_PER_PEER))
+        .with_max_established_incoming(Some(MAX_ESTABLISHED_INCOMING))
+        .with_max_established_outgoing(Some(MAX_ESTABLISHED_OUTGOING))
+}
+
+fn new_app_ping() -> request_response::Behaviour<PingCodec> {
+    request_response::Behaviour::new(
+        [(
+            StreamProtocol::new("/patchlan/ping/1.0.0"),
+            request_response::ProtocolSupport::Full,
+        )],
+        request_response::Config::default(),
+    )
+}
+
+fn new_gossipsub(keypair: &identity::Keypair) -> Result<gossipsub::Behaviour> {
+    let config = gossipsub::ConfigBuilder::default()
+        .message_id_fn(message_id_fn)
+        .build()
+        .map_err(|err| anyhow!(err))?;
+
+    gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(keypair.clone()), config)
+        .map_err(|err| anyhow!(err))
+}
+
+/// TCP transport wrapped in a pnet PSK handshake, noise, and yamux. The PSK handshake runs
+/// first on every new connection, so mismatched keys fail the stream before identify/relay
+/// ever run, giving true network isolation.
+fn pnet_tcp_transport(
+    keypair: &identity::Keypair,
+    psk: PreSharedKey,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let noise_config = noise::Config::new(keypair)?;
+    let yamux_config = yamux::Config::default();
+
+    Ok(
+        tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise_config)
+            .multiplex(yamux_config)
+            .boxed(),
+    )
+}
+
+#[cfg(test)]
+mod peer_manager_tests {
+    use super::*;
+
+    #[test]
+    fn on_hole_punch_failed_backs_off_exponentially() {
+        let mut manager = PeerManager::default();
+        let peer_id = PeerId::random();
+        manager.on_connected(peer_id, ConnectionKind::Relayed);
+
+        let mut previous = manager.peers[&peer_id].next_retry_at;
+        for _ in 0..3 {
+            manager.on_hole_punch_failed(peer_id);
+            let state = &manager.peers[&peer_id];
+            assert!(state.next_retry_at > previous);
+            previous = state.next_retry_at;
+        }
+        assert_eq!(manager.peers[&peer_id].failure_count, 3);
+    }
+
+    #[test]
+    fn on_hole_punch_succeeded_resets_failure_count_and_kind() {
+        let mut manager = PeerManager::default();
+        let peer_id = PeerId::random();
+        manager.on_connected(peer_id, ConnectionKind::Relayed);
+        manager.on_hole_punch_failed(peer_id);
+        manager.on_hole_punch_failed(peer_id);
+
+        manager.on_hole_punch_succeeded(peer_id);
+
+        let state = &manager.peers[&peer_id];
+        assert_eq!(state.failure_count, 0);
+        assert_eq!(state.kind, ConnectionKind::Direct);
+    }
+
+    #[test]
+    fn peers_due_for_retry_excludes_peers_still_backing_off() {
+        let mut manager = PeerManager::default();
+        let stuck = PeerId::random();
+        let fresh = PeerId::random();
+        manager.on_connected(stuck, ConnectionKind::Relayed);
+        manager.on_connected(fresh, ConnectionKind::Relayed);
+
+        // A freshly connected relayed peer has no backoff yet, so it's immediately due.
+        let due: HashSet<PeerId> = manager.peers_due_for_retry().into_iter().collect();
+        assert_eq!(due, HashSet::from([stuck, fresh]));
+
+        manager.on_hole_punch_failed(stuck);
+        assert_eq!(manager.peers_due_for_retry(), vec![fresh]);
+    }
+
+    #[test]
+    fn retry_disconnect_preserves_backoff_state() {
+        let mut manager = PeerManager::default();
+        let peer_id = PeerId::random();
+        manager.on_connected(peer_id, ConnectionKind::Relayed);
+        manager.on_hole_punch_failed(peer_id);
+        let failure_count_before = manager.peers[&peer_id].failure_count;
+        let next_retry_at_before = manager.peers[&peer_id].next_retry_at;
+
+        manager.mark_retry_disconnect(peer_id);
+        manager.on_disconnected(&peer_id);
+        manager.on_connected(peer_id, ConnectionKind::Relayed);
+
+        let state = &manager.peers[&peer_id];
+        assert_eq!(state.failure_count, failure_count_before);
+        assert_eq!(state.next_retry_at, next_retry_at_before);
+    }
+
+    #[test]
+    fn ordinary_disconnect_clears_peer_state() {
+        let mut manager = PeerManager::default();
+        let peer_id = PeerId::random();
+        manager.on_connected(peer_id, ConnectionKind::Relayed);
+        manager.on_hole_punch_failed(peer_id);
+
+        manager.on_disconnected(&peer_id);
+
+        assert!(!manager.peers.contains_key(&peer_id));
+    }
+
+    #[test]
+    fn excess_relayed_peers_sheds_oldest_relayed_first() {
+        let mut manager = PeerManager::default();
+        let direct_a = PeerId::random();
+        let direct_b = PeerId::random();
+        let oldest_relayed = PeerId::random();
+        let newest_relayed = PeerId::random();
+
+        manager.on_connected(direct_a, ConnectionKind::Direct);
+        manager.on_connected(direct_b, ConnectionKind::Direct);
+        manager.on_connected(oldest_relayed, ConnectionKind::Relayed);
+        manager.peers.get_mut(&oldest_relayed).unwrap().last_seen =
+            Instant::now() - Duration::from_secs(60);
+        manager.on_connected(newest_relayed, ConnectionKind::Relayed);
+
+        // Four peers against a ceiling of 2 (capacity = 2 * RELAYED_EXCESS_FACTOR = 3) is one
+        // over the excess threshold, so only the single oldest relayed peer is shed.
+        assert_eq!(manager.excess_relayed_peers(2), vec![oldest_relayed]);
+
+        // Nobody is shed while we're within the ceiling's excess allowance.
+        assert_eq!(manager.excess_relayed_peers(10), Vec::<PeerId>::new());
+    }
+}
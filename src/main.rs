@@ -34,7 +34,10 @@ struct GlobalOpts {
     /// Relay Server
     relay: Multiaddr,
 
-    seed: u8,
+    /// Derive a reproducible throwaway identity instead of the one from `patchlan init`
+    /// (for tests and local dev only; never set this for a real node).
+    #[arg(long, hide = true)]
+    seed: Option<u8>,
 }
 
 #[derive(clap::Subcommand, Debug, Clone)]
@@ -71,14 +74,75 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     tracing_subscriber::fmt::init();
+
+    if let Some(Command::Init(_)) = args.command {
+        let identity = patchlan::init_identity()?;
+        println!("Initialized new identity:");
+        println!("  peer id:     {}", identity.keypair.public().to_peer_id());
+        println!("  network key: {}", identity.network_key);
+        return Ok(());
+    }
+
     tracing::info!("Starting...");
 
-    let mut pl = patchlan::PatchLan::connect(args.global_opts.relay, args.global_opts.seed).await?;
+    let mut pl = patchlan::PatchLan::connect(
+        args.global_opts.relay,
+        args.global_opts.key,
+        args.global_opts.seed,
+    )
+    .await?;
 
     match args.command {
         None => pl.listen().await,
         Some(Command::Listen(_)) => pl.listen().await,
         Some(Command::Ping(ping_args)) => pl.ping(ping_args.peer_id).await,
-        Some(_) => todo!("command not yet implemented"),
+        Some(Command::Status(_)) => {
+            print_status(&pl);
+            Ok(())
+        }
+        Some(Command::Init(_)) => unreachable!("handled before connecting"),
+        Some(Command::AddRelay(_)) => todo!("command not yet implemented"),
+    }
+}
+
+fn print_status(pl: &patchlan::PatchLan) {
+    println!("peer id:      {}", pl.local_peer_id());
+    println!("relay:        {}", pl.relay_address());
+    println!(
+        "reachability: {:?} (confidence: {})",
+        pl.nat_status(),
+        pl.nat_confidence()
+    );
+
+    println!("listening on:");
+    for addr in pl.listeners() {
+        println!("  {addr}");
+    }
+
+    println!("external addresses:");
+    for addr in pl.external_addresses() {
+        println!("  {addr}");
+    }
+
+    let info = pl.network_info();
+    println!(
+        "connections:  {} established, {} pending",
+        info.connection_counters().num_established(),
+        info.connection_counters().num_pending()
+    );
+
+    let connected = pl.connected_peers();
+    println!("connected peers ({}):", connected.len());
+    for peer_id in connected {
+        println!("  {peer_id}");
+    }
+
+    let roster = pl.roster();
+    println!("roster ({}):", roster.len());
+    for (peer_id, info) in roster {
+        println!("  {peer_id}");
+        for addr in &info.addresses {
+            println!("    {addr}");
+        }
     }
 }